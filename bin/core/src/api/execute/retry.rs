@@ -0,0 +1,131 @@
+//! Retry transient failures (periphery network errors, timeouts, 5xx
+//! responses, registry token fetch failures) with exponential backoff and
+//! jitter, while failing fast on permission/validation errors that would
+//! just fail identically again.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+  pub max_attempts: usize,
+  pub base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+  fn default() -> Self {
+    Self {
+      max_attempts: 5,
+      base_delay: Duration::from_secs(2),
+    }
+  }
+}
+
+/// Best-effort classifier for whether `err` is worth retrying: connection
+/// and timeout errors, periphery 5xx responses, and token-fetch failures
+/// are transient; anything else (permission denied, bad config, validation)
+/// is assumed permanent and fails fast.
+fn is_transient(err: &anyhow::Error) -> bool {
+  let msg = format!("{err:#}").to_lowercase();
+  [
+    "timed out",
+    "timeout",
+    "connection refused",
+    "connection reset",
+    "connect error",
+    "dns error",
+    "failed to get registry token",
+    " 502",
+    " 503",
+    " 504",
+  ]
+  .iter()
+  .any(|needle| msg.contains(needle))
+}
+
+/// Run `f`, retrying on transient failures with exponential backoff plus
+/// jitter. `on_retry` is called with a human-readable line (e.g. "attempt
+/// 2/5 after 4s") before each retry's sleep, so the caller can append it to
+/// `update.logs`.
+pub async fn with_retry<T, F, Fut>(
+  config: RetryConfig,
+  mut on_retry: impl FnMut(String),
+  mut f: F,
+) -> anyhow::Result<T>
+where
+  F: FnMut() -> Fut,
+  Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+  let mut attempt = 1;
+  loop {
+    match f().await {
+      Ok(value) => return Ok(value),
+      Err(e) if attempt < config.max_attempts && is_transient(&e) => {
+        let delay = config.base_delay
+          * 2u32.saturating_pow((attempt - 1) as u32);
+        let jitter = Duration::from_millis(
+          rand::thread_rng().gen_range(0..250),
+        );
+        let delay = delay + jitter;
+        on_retry(format!(
+          "attempt {}/{} after {:.1}s ({e:#})",
+          attempt + 1,
+          config.max_attempts,
+          delay.as_secs_f32(),
+        ));
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn transient_network_errors_are_retried() {
+    for msg in [
+      "operation timed out",
+      "request timeout",
+      "connection refused",
+      "connection reset by peer",
+      "tcp connect error",
+      "dns error: no record found",
+      "failed to get registry token in call to db",
+      "periphery returned 502 Bad Gateway",
+      "periphery returned 503 Service Unavailable",
+      "periphery returned 504 Gateway Timeout",
+    ] {
+      assert!(
+        is_transient(&anyhow::anyhow!("{msg}")),
+        "expected {msg:?} to be classified as transient"
+      );
+    }
+  }
+
+  #[test]
+  fn permanent_errors_are_not_retried() {
+    for msg in [
+      "permission denied",
+      "invalid compose file",
+      "stack not found",
+      "400 Bad Request",
+    ] {
+      assert!(
+        !is_transient(&anyhow::anyhow!("{msg}")),
+        "expected {msg:?} to be classified as permanent"
+      );
+    }
+  }
+
+  #[test]
+  fn classification_looks_through_error_context_chain() {
+    let err = anyhow::anyhow!("connection refused")
+      .context("failed to reach periphery");
+    assert!(is_transient(&err));
+  }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use anyhow::Context;
 use formatting::format_serror;
 use interpolate::Interpolator;
@@ -7,13 +9,14 @@ use komodo_client::{
     permission::PermissionLevel,
     repo::Repo,
     server::Server,
-    stack::{Stack, StackInfo},
+    stack::{RollingDeployConfig, Stack, StackInfo},
     update::{Log, Update},
   },
 };
 use mungos::mongodb::bson::{doc, to_document};
 use periphery_client::api::compose::*;
 use resolver_api::Resolve;
+use tracing::{error, warn};
 
 use crate::{
   api::write::WriteArgs,
@@ -30,6 +33,10 @@ use crate::{
   state::{action_states, db_client},
 };
 
+use super::diff::diff_stack_info;
+use super::hooks::{HookContext, run_hook};
+use super::queue;
+use super::retry::{RetryConfig, with_retry};
 use super::{ExecuteArgs, ExecuteRequest};
 
 impl super::BatchExecute for BatchDeployStack {
@@ -62,7 +69,7 @@ impl Resolve<ExecuteArgs> for DeployStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    let (mut stack, server) = get_stack_and_server(
+    let (stack, server) = get_stack_and_server(
       &self.stack,
       user,
       PermissionLevel::Execute.into(),
@@ -70,184 +77,500 @@ impl Resolve<ExecuteArgs> for DeployStack {
     )
     .await?;
 
-    let mut repo = if !stack.config.files_on_host
-      && !stack.config.linked_repo.is_empty()
-    {
-      crate::resource::get::<Repo>(&stack.config.linked_repo)
-        .await?
-        .into()
-    } else {
-      None
-    };
+    // Allocate a globally monotonic id and durably enqueue the request
+    // rather than rejecting it if the stack happens to be busy right now.
+    // `queue::run_exclusive` (below) guarantees only one of these runs
+    // against `stack.id` at a time, so requests are processed strictly
+    // in the order they were enqueued.
+    let queue_id = queue::next_update_id().await?;
+    queue::enqueue(queue_id, &stack.id, &server.id).await?;
 
-    // get the action state for the stack (or insert default).
-    let action_state =
-      action_states().stack.get_or_insert_default(&stack.id).await;
+    let mut update = update.clone();
+    update.push_simple_log(
+      "Queued",
+      format!(
+        "Deploy for stack {} queued as pending_queue entry {queue_id}",
+        stack.name
+      ),
+    );
+    update_update(update.clone()).await?;
 
-    // Will check to ensure stack not already busy before updating, and return Err if so.
-    // The returned guard will set the action state back to default when dropped.
-    let _action_guard =
-      action_state.update(|state| state.deploying = true)?;
+    let stack_id = stack.id.clone();
+    let spawned_update = update.clone();
+    tokio::spawn(async move {
+      let res = queue::run_exclusive(queue_id, &stack_id, || async {
+        // `deploy_stack_inner` hands back the `Update` it was mutating
+        // even on failure, so the logs accumulated up to the point of
+        // failure (hook output, retry attempts, rolling batch progress)
+        // still get persisted instead of discarded in favor of a stale
+        // pre-run clone.
+        match deploy_stack_inner(self, stack, server, spawned_update)
+          .await
+        {
+          Ok(update) => Ok(update),
+          Err((e, mut update)) => {
+            let message = format_serror(&e.into());
+            update.push_error_log("Deploy", message.clone());
+            update.finalize();
+            let _ = update_update(update).await;
+            Err(anyhow::anyhow!(message))
+          }
+        }
+      })
+      .await;
+      if let Err(e) = res {
+        error!(
+          "pending_queue entry {queue_id} (deploy) failed | {e:#}"
+        );
+      }
+    });
 
-    let mut update = update.clone();
+    // Return immediately with the queued update so callers can poll
+    // `update.id` for progress/completion.
+    Ok(update)
+  }
+}
 
-    update_update(update.clone()).await?;
+async fn deploy_stack_inner(
+  req: DeployStack,
+  mut stack: Stack,
+  server: Server,
+  mut update: Update,
+) -> Result<Update, (anyhow::Error, Update)> {
+  match deploy_stack_inner_fallible(&req, &mut stack, &server, &mut update)
+    .await
+  {
+    Ok(()) => Ok(update),
+    Err(e) => Err((e, update)),
+  }
+}
 
-    if !self.services.is_empty() {
-      update.logs.push(Log::simple(
-        "Service/s",
-        format!(
-          "Execution requested for Stack service/s {}",
-          self.services.join(", ")
-        ),
-      ))
-    }
+/// The fallible body of [`deploy_stack_inner`], split out so a failure
+/// partway through (hook throw, retries exhausted, rolling batch
+/// failure) still leaves every log line accumulated in `update` up to
+/// that point available to the caller, instead of being thrown away
+/// along with the error.
+async fn deploy_stack_inner_fallible(
+  req: &DeployStack,
+  stack: &mut Stack,
+  server: &Server,
+  update: &mut Update,
+) -> anyhow::Result<()> {
+  let mut repo = if !stack.config.files_on_host
+    && !stack.config.linked_repo.is_empty()
+  {
+    crate::resource::get::<Repo>(&stack.config.linked_repo)
+      .await?
+      .into()
+  } else {
+    None
+  };
 
-    let git_token =
-      stack_git_token(&mut stack, repo.as_mut()).await?;
+  // get the action state for the stack (or insert default).
+  let action_state =
+    action_states().stack.get_or_insert_default(&stack.id).await;
 
-    let registry_token = crate::helpers::registry_token(
-      &stack.config.registry_provider,
-      &stack.config.registry_account,
-    ).await.with_context(
-      || format!("Failed to get registry token in call to db. Stopping run. | {} | {}", stack.config.registry_provider, stack.config.registry_account),
-    )?;
+  // `run_exclusive` already serializes this against other queued
+  // deploys/pulls of this stack. Operations that don't go through the
+  // queue (start, restart, pause, unpause, stop, destroy, rollback) take
+  // this same guard directly, so this still returns Err if one of them
+  // holds it. The flag also makes the UI show the stack as deploying.
+  let _action_guard =
+    action_state.update(|state| state.deploying = true)?;
 
-    // interpolate variables / secrets, returning the sanitizing replacers to send to
-    // periphery so it may sanitize the final command for safe logging (avoids exposing secret values)
-    let secret_replacers = if !stack.config.skip_secret_interp {
-      let VariablesAndSecrets { variables, secrets } =
-        get_variables_and_secrets().await?;
+  if !req.services.is_empty() {
+    update.logs.push(Log::simple(
+      "Service/s",
+      format!(
+        "Execution requested for Stack service/s {}",
+        req.services.join(", ")
+      ),
+    ))
+  }
 
-      let mut interpolator =
-        Interpolator::new(Some(&variables), &secrets);
+  let git_token = stack_git_token(stack, repo.as_mut()).await?;
 
-      interpolator.interpolate_stack(&mut stack)?;
-      if let Some(repo) = repo.as_mut() {
-        if !repo.config.skip_secret_interp {
-          interpolator.interpolate_repo(repo)?;
-        }
+  let registry_token = with_retry(
+    RetryConfig::default(),
+    |msg| update.logs.push(Log::simple("Retry", msg)),
+    || async {
+      crate::helpers::registry_token(
+        &stack.config.registry_provider,
+        &stack.config.registry_account,
+      ).await.with_context(
+        || format!("Failed to get registry token in call to db. Stopping run. | {} | {}", stack.config.registry_provider, stack.config.registry_account),
+      )
+    },
+  ).await?;
+
+  // interpolate variables / secrets, returning the sanitizing replacers to send to
+  // periphery so it may sanitize the final command for safe logging (avoids exposing secret values)
+  let secret_replacers = if !stack.config.skip_secret_interp {
+    let VariablesAndSecrets { variables, secrets } =
+      get_variables_and_secrets().await?;
+
+    let mut interpolator =
+      Interpolator::new(Some(&variables), &secrets);
+
+    interpolator.interpolate_stack(stack)?;
+    if let Some(repo) = repo.as_mut() {
+      if !repo.config.skip_secret_interp {
+        interpolator.interpolate_repo(repo)?;
       }
-      interpolator.push_logs(&mut update.logs);
+    }
+    interpolator.push_logs(&mut update.logs);
 
-      interpolator.secret_replacers
+    interpolator.secret_replacers
+  } else {
+    Default::default()
+  };
+
+  // Keep what's needed to redeploy the last-known-good snapshot around,
+  // in case this deploy fails and `rollback_on_failure` is set.
+  let rollback_inputs = stack.config.rollback_on_failure.then(|| {
+    (repo.clone(), git_token.clone(), registry_token.clone())
+  });
+
+  let services = req.services.clone();
+  let replacers: Vec<_> = secret_replacers.into_iter().collect();
+
+  let pre_deploy_ctx = HookContext {
+    stack: stack.name.clone(),
+    project_name: stack.project_name(true),
+    services: services.clone(),
+    commit_hash: None,
+    commit_message: None,
+  };
+  if !run_hook(
+    &stack.config.pre_deploy,
+    "pre_deploy",
+    &pre_deploy_ctx,
+    update,
+  )
+  .await
+  {
+    anyhow::bail!("pre_deploy hook failed, aborting deploy");
+  }
+
+  // `services` is empty on the default "deploy the whole stack" request
+  // (both the direct call and `BatchDeployStack`'s `single_request`), so
+  // a rolling deploy can't key off it being non-empty - that would make
+  // `rolling_deploy` unreachable from the path it's meant to protect.
+  // Fall back to the stack's known service set in that case.
+  let rolling_services = if services.is_empty() {
+    stack.info.latest_services.clone()
+  } else {
+    services.clone()
+  };
+
+  let compose_res = match stack
+    .config
+    .rolling_deploy
+    .clone()
+    .filter(|_| !rolling_services.is_empty())
+  {
+    Some(rolling) => {
+      deploy_rolling(
+        server, stack, &repo, &git_token, &registry_token,
+        &replacers, &rolling_services, &rolling, update,
+      )
+      .await?
+    }
+    None => {
+      with_retry(
+        RetryConfig::default(),
+        |msg| update.logs.push(Log::simple("Retry", msg)),
+        || async {
+          periphery_client(server)?
+            .request(ComposeUp {
+              stack: stack.clone(),
+              services: services.clone(),
+              repo: repo.clone(),
+              git_token: git_token.clone(),
+              registry_token: registry_token.clone(),
+              replacers: replacers.clone(),
+            })
+            .await
+        },
+      )
+      .await?
+    }
+  };
+
+  let ComposeUpResponse {
+    logs,
+    deployed,
+    services: returned_services,
+    file_contents,
+    missing_files,
+    remote_errors,
+    compose_config,
+    commit_hash,
+    commit_message,
+  } = compose_res;
+  let services = returned_services;
+
+  update.logs.extend(logs);
+
+  let post_deploy_ctx = HookContext {
+    stack: stack.name.clone(),
+    project_name: stack.project_name(true),
+    services: services.clone(),
+    commit_hash: commit_hash.clone(),
+    commit_message: commit_message.clone(),
+  };
+  run_hook(
+    &stack.config.post_deploy,
+    "post_deploy",
+    &post_deploy_ctx,
+    update,
+  )
+  .await;
+
+  if !deployed {
+    if let Some((repo, git_token, registry_token)) = rollback_inputs {
+      rollback_to_last_known_good(
+        server, stack, repo, git_token, registry_token, update,
+      )
+      .await;
+    }
+  }
+
+  let update_info = async {
+    let latest_services = if services.is_empty() {
+      // maybe better to do something else here for services.
+      stack.info.latest_services.clone()
     } else {
-      Default::default()
+      services
     };
 
-    let ComposeUpResponse {
-      logs,
-      deployed,
-      services,
-      file_contents,
+    // This ensures to get the latest project name,
+    // as it may have changed since the last deploy.
+    let project_name = stack.project_name(true);
+
+    let (
+      deployed_services,
+      deployed_contents,
+      deployed_config,
+      deployed_hash,
+      deployed_message,
+    ) = if deployed {
+      (
+        Some(latest_services.clone()),
+        Some(file_contents.clone()),
+        compose_config,
+        commit_hash.clone(),
+        commit_message.clone(),
+      )
+    } else {
+      (
+        stack.info.deployed_services.clone(),
+        stack.info.deployed_contents.clone(),
+        stack.info.deployed_config.clone(),
+        stack.info.deployed_hash.clone(),
+        stack.info.deployed_message.clone(),
+      )
+    };
+
+    let info = StackInfo {
       missing_files,
-      remote_errors,
-      compose_config,
-      commit_hash,
-      commit_message,
-    } = periphery_client(&server)?
-      .request(ComposeUp {
-        stack: stack.clone(),
-        services: self.services,
-        repo,
-        git_token,
-        registry_token,
-        replacers: secret_replacers.into_iter().collect(),
-      })
-      .await?;
+      deployed_project_name: project_name.into(),
+      deployed_services,
+      deployed_contents,
+      deployed_config,
+      deployed_hash,
+      deployed_message,
+      latest_services,
+      remote_contents: stack
+        .config
+        .file_contents
+        .is_empty()
+        .then_some(file_contents),
+      remote_errors: stack
+        .config
+        .file_contents
+        .is_empty()
+        .then_some(remote_errors),
+      latest_hash: commit_hash,
+      latest_message: commit_message,
+    };
 
-    update.logs.extend(logs);
+    let info = to_document(&info)
+      .context("failed to serialize stack info to bson")?;
 
-    let update_info = async {
-      let latest_services = if services.is_empty() {
-        // maybe better to do something else here for services.
-        stack.info.latest_services.clone()
-      } else {
-        services
-      };
+    db_client()
+      .stacks
+      .update_one(
+        doc! { "name": &stack.name },
+        doc! { "$set": { "info": info } },
+      )
+      .await
+      .context("failed to update stack info on db")?;
+    anyhow::Ok(())
+  };
 
-      // This ensures to get the latest project name,
-      // as it may have changed since the last deploy.
-      let project_name = stack.project_name(true);
-
-      let (
-        deployed_services,
-        deployed_contents,
-        deployed_config,
-        deployed_hash,
-        deployed_message,
-      ) = if deployed {
-        (
-          Some(latest_services.clone()),
-          Some(file_contents.clone()),
-          compose_config,
-          commit_hash.clone(),
-          commit_message.clone(),
-        )
-      } else {
-        (
-          stack.info.deployed_services,
-          stack.info.deployed_contents,
-          stack.info.deployed_config,
-          stack.info.deployed_hash,
-          stack.info.deployed_message,
-        )
-      };
+  // This will be weird with single service deploys. Come back to it.
+  if let Err(e) = update_info.await {
+    update.push_error_log(
+      "refresh stack info",
+      format_serror(
+        &e.context("failed to refresh stack info on db").into(),
+      ),
+    )
+  }
 
-      let info = StackInfo {
-        missing_files,
-        deployed_project_name: project_name.into(),
-        deployed_services,
-        deployed_contents,
-        deployed_config,
-        deployed_hash,
-        deployed_message,
-        latest_services,
-        remote_contents: stack
-          .config
-          .file_contents
-          .is_empty()
-          .then_some(file_contents),
-        remote_errors: stack
-          .config
-          .file_contents
-          .is_empty()
-          .then_some(remote_errors),
-        latest_hash: commit_hash,
-        latest_message: commit_message,
-      };
+  // Ensure cached stack state up to date by updating server cache
+  update_cache_for_server(server).await;
 
-      let info = to_document(&info)
-        .context("failed to serialize stack info to bson")?;
+  update.finalize();
+  update_update(update.clone()).await?;
 
-      db_client()
-        .stacks
-        .update_one(
-          doc! { "name": &stack.name },
-          doc! { "$set": { "info": info } },
-        )
-        .await
-        .context("failed to update stack info on db")?;
-      anyhow::Ok(())
-    };
+  Ok(())
+}
+
+/// Deploy `services` in ordered batches of `rolling.batch_size`, polling
+/// periphery for container health after each batch and only proceeding
+/// once it reports healthy within `rolling.health_timeout_secs`. Stops
+/// (and reports `deployed = false`) on the first batch that fails to
+/// deploy or to become healthy.
+async fn deploy_rolling(
+  server: &Server,
+  stack: &Stack,
+  repo: &Option<Repo>,
+  git_token: &Option<String>,
+  registry_token: &Option<String>,
+  replacers: &[(String, String)],
+  services: &[String],
+  rolling: &RollingDeployConfig,
+  update: &mut Update,
+) -> anyhow::Result<ComposeUpResponse> {
+  let batch_size = rolling.batch_size.max(1);
+  let health_timeout =
+    Duration::from_secs(rolling.health_timeout_secs.max(1));
+  let batch_count = services.len().div_ceil(batch_size);
+
+  let mut total_logs = Vec::new();
+  // Accumulated across every batch, not just the last one, so
+  // `StackInfo.latest_services`/`deployed_services` reflect the union of
+  // everything a rolling deploy actually touched.
+  let mut total_services = Vec::new();
+
+  for (i, batch) in services.chunks(batch_size).enumerate() {
+    update.push_simple_log(
+      "Rolling deploy",
+      format!(
+        "Deploying batch {}/{batch_count} ({} service/s): {}",
+        i + 1,
+        batch.len(),
+        batch.join(", ")
+      ),
+    );
+
+    let res = with_retry(
+      RetryConfig::default(),
+      |msg| update.logs.push(Log::simple("Retry", msg)),
+      || async {
+        periphery_client(server)?
+          .request(ComposeUp {
+            stack: stack.clone(),
+            services: batch.to_vec(),
+            repo: repo.clone(),
+            git_token: git_token.clone(),
+            registry_token: registry_token.clone(),
+            replacers: replacers.to_vec(),
+          })
+          .await
+      },
+    )
+    .await?;
 
-    // This will be weird with single service deploys. Come back to it.
-    if let Err(e) = update_info.await {
+    total_logs.extend(res.logs.clone());
+    total_services.extend(res.services.clone());
+
+    if !res.deployed {
       update.push_error_log(
-        "refresh stack info",
-        format_serror(
-          &e.context("failed to refresh stack info on db").into(),
+        "Rolling deploy",
+        format!(
+          "Batch {}/{batch_count} failed to deploy, stopping rolling deploy.",
+          i + 1
         ),
-      )
+      );
+      return Ok(ComposeUpResponse {
+        logs: total_logs,
+        deployed: false,
+        services: total_services,
+        ..res
+      });
     }
 
-    // Ensure cached stack state up to date by updating server cache
-    update_cache_for_server(&server).await;
+    let healthy = wait_for_batch_healthy(
+      server,
+      stack,
+      batch,
+      health_timeout,
+      rolling.health_command.as_deref(),
+    )
+    .await?;
 
-    update.finalize();
-    update_update(update.clone()).await?;
+    if !healthy {
+      update.push_error_log(
+        "Rolling deploy",
+        format!(
+          "Batch {}/{batch_count} did not report healthy within {health_timeout:?}, stopping rolling deploy.",
+          i + 1
+        ),
+      );
+      return Ok(ComposeUpResponse {
+        logs: total_logs,
+        deployed: false,
+        services: total_services,
+        ..res
+      });
+    }
 
-    Ok(update)
+    update.push_simple_log(
+      "Rolling deploy",
+      format!("Batch {}/{batch_count} healthy.", i + 1),
+    );
+
+    if i + 1 == batch_count {
+      return Ok(ComposeUpResponse {
+        logs: total_logs,
+        services: total_services,
+        ..res
+      });
+    }
+  }
+
+  anyhow::bail!("rolling_deploy configured with no services to deploy")
+}
+
+/// Poll periphery for the health of every service in `batch` until all
+/// are healthy or `timeout` elapses.
+async fn wait_for_batch_healthy(
+  server: &Server,
+  stack: &Stack,
+  batch: &[String],
+  timeout: Duration,
+  health_command: Option<&str>,
+) -> anyhow::Result<bool> {
+  let deadline = tokio::time::Instant::now() + timeout;
+  loop {
+    let health = periphery_client(server)?
+      .request(GetComposeServiceHealth {
+        stack: stack.name.clone(),
+        services: batch.to_vec(),
+        command: health_command.map(str::to_string),
+      })
+      .await?;
+
+    if health.all_healthy {
+      return Ok(true);
+    }
+    if tokio::time::Instant::now() >= deadline {
+      return Ok(false);
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
   }
 }
 
@@ -295,30 +618,20 @@ impl Resolve<ExecuteArgs> for DeployStackIfChanged {
     .resolve(&WriteArgs { user: user.clone() })
     .await?;
     let stack = resource::get::<Stack>(&stack.id).await?;
-    let changed = match (
-      &stack.info.deployed_contents,
-      &stack.info.remote_contents,
-    ) {
-      (Some(deployed_contents), Some(latest_contents)) => {
-        let changed = || {
-          for latest in latest_contents {
-            let Some(deployed) = deployed_contents
-              .iter()
-              .find(|c| c.path == latest.path)
-            else {
-              return true;
-            };
-            if latest.contents != deployed.contents {
-              return true;
-            }
-          }
-          false
-        };
-        changed()
-      }
-      (None, _) => true,
-      _ => false,
-    };
+    // Reuse `PreviewStackDeploy`'s own structured diff (file contents
+    // *and* resolved compose config) rather than re-deriving `changed`
+    // from file contents alone, so the two can never disagree on a
+    // compose-level-only change (e.g. a resolved image tag shifting from
+    // an updated variable with the raw file text unchanged).
+    let diff = PreviewStackDeploy {
+      stack: stack.name.clone(),
+    }
+    .resolve(&ExecuteArgs {
+      user: user.clone(),
+      update: update.clone(),
+    })
+    .await?;
+    let changed = diff.changed();
 
     let mut update = update.clone();
 
@@ -392,12 +705,22 @@ pub async fn pull_stack_inner(
 
   let git_token = stack_git_token(&mut stack, repo.as_mut()).await?;
 
-  let registry_token = crate::helpers::registry_token(
-      &stack.config.registry_provider,
-      &stack.config.registry_account,
-    ).await.with_context(
-      || format!("Failed to get registry token in call to db. Stopping run. | {} | {}", stack.config.registry_provider, stack.config.registry_account),
-    )?;
+  let registry_token = with_retry(
+    RetryConfig::default(),
+    |msg| {
+      if let Some(update) = update.as_deref_mut() {
+        update.logs.push(Log::simple("Retry", msg));
+      }
+    },
+    || async {
+      crate::helpers::registry_token(
+        &stack.config.registry_provider,
+        &stack.config.registry_account,
+      ).await.with_context(
+        || format!("Failed to get registry token in call to db. Stopping run. | {} | {}", stack.config.registry_provider, stack.config.registry_account),
+      )
+    },
+  ).await?;
 
   // interpolate variables / secrets
   let secret_replacers = if !stack.config.skip_secret_interp {
@@ -413,7 +736,7 @@ pub async fn pull_stack_inner(
         interpolator.interpolate_repo(repo)?;
       }
     }
-    if let Some(update) = update {
+    if let Some(update) = update.as_deref_mut() {
       interpolator.push_logs(&mut update.logs);
     }
     interpolator.secret_replacers
@@ -421,16 +744,29 @@ pub async fn pull_stack_inner(
     Default::default()
   };
 
-  let res = periphery_client(server)?
-    .request(ComposePull {
-      stack,
-      services,
-      repo,
-      git_token,
-      registry_token,
-      replacers: secret_replacers.into_iter().collect(),
-    })
-    .await?;
+  let replacers: Vec<_> = secret_replacers.into_iter().collect();
+
+  let res = with_retry(
+    RetryConfig::default(),
+    |msg| {
+      if let Some(update) = update.as_deref_mut() {
+        update.logs.push(Log::simple("Retry", msg));
+      }
+    },
+    || async {
+      periphery_client(server)?
+        .request(ComposePull {
+          stack: stack.clone(),
+          services: services.clone(),
+          repo: repo.clone(),
+          git_token: git_token.clone(),
+          registry_token: registry_token.clone(),
+          replacers: replacers.clone(),
+        })
+        .await
+    },
+  )
+  .await?;
 
   // Ensure cached stack state up to date by updating server cache
   update_cache_for_server(server).await;
@@ -462,48 +798,126 @@ impl Resolve<ExecuteArgs> for PullStack {
       None
     };
 
-    // get the action state for the stack (or insert default).
-    let action_state =
-      action_states().stack.get_or_insert_default(&stack.id).await;
-
-    // Will check to ensure stack not already busy before updating, and return Err if so.
-    // The returned guard will set the action state back to default when dropped.
-    let _action_guard =
-      action_state.update(|state| state.pulling = true)?;
+    // Durably enqueue instead of rejecting when the stack is already
+    // busy; `queue::run_exclusive` serializes this against any other
+    // queued operation on the same stack.
+    let queue_id = queue::next_update_id().await?;
+    queue::enqueue(queue_id, &stack.id, &server.id).await?;
 
     let mut update = update.clone();
+    update.push_simple_log(
+      "Queued",
+      format!(
+        "Pull for stack {} queued as pending_queue entry {queue_id}",
+        stack.name
+      ),
+    );
     update_update(update.clone()).await?;
 
-    let res = pull_stack_inner(
-      stack,
-      self.services,
-      &server,
-      repo,
-      Some(&mut update),
-    )
-    .await?;
-
-    update.logs.extend(res.logs);
-    update.finalize();
-    update_update(update.clone()).await?;
+    let stack_id = stack.id.clone();
+    let services = self.services;
+    let spawned_update = update.clone();
+    // Spawned (not awaited) so this returns immediately with the queued
+    // `Update`, the same as `DeployStack` - previously `PullStack` still
+    // blocked for the full pull+retries before responding, which was
+    // inconsistent with both `DeployStack`'s behavior and the "callers
+    // poll `update.id`" design.
+    tokio::spawn(async move {
+      let res = queue::run_exclusive(queue_id, &stack_id, || async {
+        match pull_stack_entry(stack, services, server, repo, spawned_update)
+          .await
+        {
+          Ok(update) => Ok(update),
+          Err((e, mut update)) => {
+            let message = format_serror(&e.into());
+            update.push_error_log("Pull", message.clone());
+            update.finalize();
+            let _ = update_update(update).await;
+            Err(anyhow::anyhow!(message))
+          }
+        }
+      })
+      .await;
+      if let Err(e) = res {
+        error!("pending_queue entry {queue_id} (pull) failed | {e:#}");
+      }
+    });
 
     Ok(update)
   }
 }
 
+async fn pull_stack_entry(
+  stack: Stack,
+  services: Vec<String>,
+  server: Server,
+  repo: Option<Repo>,
+  mut update: Update,
+) -> Result<Update, (anyhow::Error, Update)> {
+  match pull_stack_entry_fallible(&mut update, stack, services, &server, repo)
+    .await
+  {
+    Ok(()) => Ok(update),
+    Err(e) => Err((e, update)),
+  }
+}
+
+async fn pull_stack_entry_fallible(
+  update: &mut Update,
+  stack: Stack,
+  services: Vec<String>,
+  server: &Server,
+  repo: Option<Repo>,
+) -> anyhow::Result<()> {
+  // get the action state for the stack (or insert default).
+  let action_state =
+    action_states().stack.get_or_insert_default(&stack.id).await;
+  let _action_guard =
+    action_state.update(|state| state.pulling = true)?;
+
+  let res =
+    pull_stack_inner(stack, services, server, repo, Some(update)).await?;
+
+  update.logs.extend(res.logs);
+  update.finalize();
+  update_update(update.clone()).await?;
+
+  Ok(())
+}
+
 impl Resolve<ExecuteArgs> for StartStack {
   #[instrument(name = "StartStack", skip(user, update), fields(user_id = user.id))]
   async fn resolve(
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<StartStack>(
-      &self.stack,
-      self.services,
-      user,
-      |state| state.starting = true,
-      update.clone(),
-      (),
+    let services = self.services;
+    // Acquire the guard once, up front, and hold it across every retry
+    // attempt below. `execute_compose` also flips this same flag, but it
+    // does so (and releases it) once per call - retrying `execute_compose`
+    // directly would only reserve the stack for the duration of each
+    // attempt, leaving it free for a conflicting op to sneak in during the
+    // backoff sleep between attempts. Passing a no-op setter into
+    // `execute_compose` means its own internal flip is a no-op against the
+    // guard we're already holding.
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.starting = true)?;
+    with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<StartStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          (),
+        )
+        .await
+      },
     )
     .await
     .map_err(Into::into)
@@ -516,15 +930,25 @@ impl Resolve<ExecuteArgs> for RestartStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<RestartStack>(
-      &self.stack,
-      self.services,
-      user,
-      |state| {
-        state.restarting = true;
+    let services = self.services;
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.restarting = true)?;
+    with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<RestartStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          (),
+        )
+        .await
       },
-      update.clone(),
-      (),
     )
     .await
     .map_err(Into::into)
@@ -537,13 +961,25 @@ impl Resolve<ExecuteArgs> for PauseStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<PauseStack>(
-      &self.stack,
-      self.services,
-      user,
-      |state| state.pausing = true,
-      update.clone(),
-      (),
+    let services = self.services;
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.pausing = true)?;
+    with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<PauseStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          (),
+        )
+        .await
+      },
     )
     .await
     .map_err(Into::into)
@@ -556,13 +992,25 @@ impl Resolve<ExecuteArgs> for UnpauseStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<UnpauseStack>(
-      &self.stack,
-      self.services,
-      user,
-      |state| state.unpausing = true,
-      update.clone(),
-      (),
+    let services = self.services;
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.unpausing = true)?;
+    with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<UnpauseStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          (),
+        )
+        .await
+      },
     )
     .await
     .map_err(Into::into)
@@ -575,16 +1023,42 @@ impl Resolve<ExecuteArgs> for StopStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<StopStack>(
-      &self.stack,
-      self.services,
-      user,
-      |state| state.stopping = true,
-      update.clone(),
-      self.stop_time,
+    let services = self.services;
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.stopping = true)?;
+    let mut update = with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<StopStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          self.stop_time,
+        )
+        .await
+      },
     )
-    .await
-    .map_err(Into::into)
+    .await?;
+
+    if let Ok(stack) = resource::get::<Stack>(&self.stack).await {
+      let ctx = HookContext {
+        stack: stack.name.clone(),
+        project_name: stack.project_name(true),
+        services,
+        commit_hash: None,
+        commit_message: None,
+      };
+      run_hook(&stack.config.post_stop, "post_stop", &ctx, &mut update)
+        .await;
+      update_update(update.clone()).await?;
+    }
+
+    Ok(update)
   }
 }
 
@@ -618,15 +1092,395 @@ impl Resolve<ExecuteArgs> for DestroyStack {
     self,
     ExecuteArgs { user, update }: &ExecuteArgs,
   ) -> serror::Result<Update> {
-    execute_compose::<DestroyStack>(
+    let mut update = update.clone();
+    // Used both for the `pre_destroy` hook context and, cloned again, for
+    // each retried `execute_compose` attempt below.
+    let services = self.services.clone();
+
+    if let Ok(stack) = resource::get::<Stack>(&self.stack).await {
+      let ctx = HookContext {
+        stack: stack.name.clone(),
+        project_name: stack.project_name(true),
+        services: services.clone(),
+        commit_hash: None,
+        commit_message: None,
+      };
+      if !run_hook(
+        &stack.config.pre_destroy,
+        "pre_destroy",
+        &ctx,
+        &mut update,
+      )
+      .await
+      {
+        update.push_error_log(
+          "pre_destroy",
+          "pre_destroy hook failed, aborting destroy.".to_string(),
+        );
+        update.finalize();
+        update_update(update.clone()).await?;
+        return Ok(update);
+      }
+    }
+
+    let action_state =
+      action_states().stack.get_or_insert_default(&self.stack).await;
+    let _action_guard =
+      action_state.update(|state| state.destroying = true)?;
+    with_retry(
+      RetryConfig::default(),
+      |msg| warn!("{msg}"),
+      || async {
+        execute_compose::<DestroyStack>(
+          &self.stack,
+          services.clone(),
+          user,
+          |_| {},
+          update.clone(),
+          (self.stop_time, self.remove_orphans),
+        )
+        .await
+      },
+    )
+    .await
+    .map_err(Into::into)
+  }
+}
+
+/// On a failed `ComposeUp` (`deployed == false`) with `rollback_on_failure`
+/// set, re-issue `ComposeUp` with the stack's file contents replaced by
+/// the previously stored `deployed_contents` so the stack doesn't sit half
+/// updated. `deployed_hash` is only logged; no commit is checked out. `update.logs` records the attempt either way; `StackInfo` is
+/// left untouched since it already points at the last-known-good
+/// snapshot whenever `deployed == false`.
+async fn rollback_to_last_known_good(
+  server: &Server,
+  stack: &Stack,
+  mut repo: Option<Repo>,
+  git_token: Option<String>,
+  registry_token: Option<String>,
+  update: &mut Update,
+) {
+  let Some(deployed_contents) = stack.info.deployed_contents.clone()
+  else {
+    update.push_error_log(
+      "Rollback",
+      "Deploy failed and rollback_on_failure is set, but there is no \
+       previously deployed snapshot to roll back to."
+        .to_string(),
+    );
+    return;
+  };
+
+  update.push_simple_log(
+    "Rollback",
+    format!(
+      "Deploy failed, rolling back to last-known-good commit {}",
+      stack.info.deployed_hash.as_deref().unwrap_or("unknown")
+    ),
+  );
+
+  let mut rollback_stack = stack.clone();
+  rollback_stack.config.file_contents = deployed_contents;
+
+  // Interpolate the rollback content itself, not the live/current stack
+  // config, so the replacers sent to periphery actually cover whatever
+  // secrets `deployed_contents` references - those can differ from the
+  // current config's secrets (e.g. one no longer in use), and an
+  // interpolation computed against the live config would miss them,
+  // letting the old secret's raw value reach `update.logs`.
+  let replacers: Vec<_> = if !rollback_stack.config.skip_secret_interp {
+    match get_variables_and_secrets().await {
+      Ok(VariablesAndSecrets { variables, secrets }) => {
+        let mut interpolator =
+          Interpolator::new(Some(&variables), &secrets);
+        if let Err(e) = interpolator.interpolate_stack(&mut rollback_stack)
+        {
+          update.push_error_log(
+            "Rollback",
+            format_serror(
+              &e.context("failed to interpolate rollback stack").into(),
+            ),
+          );
+        }
+        if let Some(repo) = repo.as_mut() {
+          if !repo.config.skip_secret_interp {
+            if let Err(e) = interpolator.interpolate_repo(repo) {
+              update.push_error_log(
+                "Rollback",
+                format_serror(
+                  &e
+                    .context("failed to interpolate rollback repo")
+                    .into(),
+                ),
+              );
+            }
+          }
+        }
+        interpolator.push_logs(&mut update.logs);
+        interpolator.secret_replacers.into_iter().collect()
+      }
+      Err(e) => {
+        update.push_error_log(
+          "Rollback",
+          format_serror(
+            &e.context("failed to get variables/secrets").into(),
+          ),
+        );
+        Default::default()
+      }
+    }
+  } else {
+    Default::default()
+  };
+
+  let res = with_retry(
+    RetryConfig::default(),
+    |msg| update.push_simple_log("Retry", msg),
+    || async {
+      periphery_client(server)?
+        .request(ComposeUp {
+          stack: rollback_stack.clone(),
+          services: Vec::new(),
+          repo: repo.clone(),
+          git_token: git_token.clone(),
+          registry_token: registry_token.clone(),
+          replacers: replacers.clone(),
+        })
+        .await
+    },
+  )
+  .await;
+
+  match res {
+    Ok(rollback_res) => {
+      update.logs.extend(rollback_res.logs);
+      if rollback_res.deployed {
+        update.push_simple_log(
+          "Rollback",
+          "Rollback succeeded, stack restored to last-known-good."
+            .to_string(),
+        );
+      } else {
+        update.push_error_log(
+          "Rollback",
+          "Rollback ComposeUp also reported deployed = false, stack \
+           may be left in a bad state."
+            .to_string(),
+        );
+      }
+    }
+    Err(e) => update.push_error_log(
+      "Rollback",
+      format_serror(&e.context("rollback ComposeUp failed").into()),
+    ),
+  }
+}
+
+impl Resolve<ExecuteArgs> for RollbackStack {
+  #[instrument(name = "RollbackStack", skip(user, update), fields(user_id = user.id, update_id = update.id))]
+  async fn resolve(
+    self,
+    ExecuteArgs { user, update }: &ExecuteArgs,
+  ) -> serror::Result<Update> {
+    let (mut stack, server) = get_stack_and_server(
+      &self.stack,
+      user,
+      PermissionLevel::Execute.into(),
+      true,
+    )
+    .await?;
+
+    // get the action state for the stack (or insert default).
+    let action_state =
+      action_states().stack.get_or_insert_default(&stack.id).await;
+
+    // Will check to ensure stack not already busy before updating, and return Err if so.
+    // The returned guard will set the action state back to default when dropped.
+    let _action_guard =
+      action_state.update(|state| state.deploying = true)?;
+
+    let mut update = update.clone();
+    update_update(update.clone()).await?;
+
+    let Some(deployed_contents) = stack.info.deployed_contents.clone()
+    else {
+      update.push_error_log(
+        "Rollback",
+        "No previously deployed snapshot exists for this stack."
+          .to_string(),
+      );
+      update.finalize();
+      update_update(update.clone()).await?;
+      return Ok(update);
+    };
+
+    let mut repo = if !stack.config.files_on_host
+      && !stack.config.linked_repo.is_empty()
+    {
+      crate::resource::get::<Repo>(&stack.config.linked_repo)
+        .await?
+        .into()
+    } else {
+      None
+    };
+    let git_token =
+      stack_git_token(&mut stack, repo.as_mut()).await?;
+
+    let registry_token = with_retry(
+      RetryConfig::default(),
+      |msg| update.logs.push(Log::simple("Retry", msg)),
+      || async {
+        crate::helpers::registry_token(
+          &stack.config.registry_provider,
+          &stack.config.registry_account,
+        )
+        .await
+        .context("Failed to get registry token in call to db")
+      },
+    )
+    .await?;
+
+    update.push_simple_log(
+      "Rollback",
+      format!(
+        "Deploying stored snapshot for commit {} regardless of current remote/latest contents.",
+        stack.info.deployed_hash.as_deref().unwrap_or("unknown")
+      ),
+    );
+
+    let mut rollback_stack = stack.clone();
+    rollback_stack.config.file_contents = deployed_contents;
+
+    // Interpolate the rollback content itself, not the live stack config,
+    // so the replacers sent to periphery cover whatever secrets
+    // `deployed_contents` references - those can differ from the
+    // current config's secrets (e.g. one no longer in use), and an
+    // interpolation computed against the live config would miss them,
+    // letting the old secret's raw value reach `update.logs`.
+    let secret_replacers = if !rollback_stack.config.skip_secret_interp {
+      let VariablesAndSecrets { variables, secrets } =
+        get_variables_and_secrets().await?;
+      let mut interpolator =
+        Interpolator::new(Some(&variables), &secrets);
+      interpolator.interpolate_stack(&mut rollback_stack)?;
+      if let Some(repo) = repo.as_mut() {
+        if !repo.config.skip_secret_interp {
+          interpolator.interpolate_repo(repo)?;
+        }
+      }
+      interpolator.push_logs(&mut update.logs);
+      interpolator.secret_replacers
+    } else {
+      Default::default()
+    };
+    let replacers: Vec<_> = secret_replacers.into_iter().collect();
+
+    let ComposeUpResponse { logs, deployed, .. } = with_retry(
+      RetryConfig::default(),
+      |msg| update.logs.push(Log::simple("Retry", msg)),
+      || async {
+        periphery_client(&server)?
+          .request(ComposeUp {
+            stack: rollback_stack.clone(),
+            services: Vec::new(),
+            repo: repo.clone(),
+            git_token: git_token.clone(),
+            registry_token: registry_token.clone(),
+            replacers: replacers.clone(),
+          })
+          .await
+      },
+    )
+    .await?;
+
+    update.logs.extend(logs);
+    if !deployed {
+      update.push_error_log(
+        "Rollback",
+        "ComposeUp reported deployed = false for the rollback."
+          .to_string(),
+      );
+    }
+
+    update_cache_for_server(&server).await;
+    update.finalize();
+    update_update(update.clone()).await?;
+
+    Ok(update)
+  }
+}
+
+impl Resolve<ExecuteArgs> for PreviewStackDeploy {
+  #[instrument(name = "PreviewStackDeploy", skip(user), fields(user_id = user.id))]
+  async fn resolve(
+    self,
+    ExecuteArgs { user, .. }: &ExecuteArgs,
+  ) -> serror::Result<StackDiff> {
+    let (mut stack, server) = get_stack_and_server(
       &self.stack,
-      self.services,
       user,
-      |state| state.destroying = true,
-      update.clone(),
-      (self.stop_time, self.remove_orphans),
+      PermissionLevel::Execute.into(),
+      true,
+    )
+    .await?;
+
+    let mut repo = if !stack.config.files_on_host
+      && !stack.config.linked_repo.is_empty()
+    {
+      crate::resource::get::<Repo>(&stack.config.linked_repo)
+        .await?
+        .into()
+    } else {
+      None
+    };
+
+    let git_token =
+      stack_git_token(&mut stack, repo.as_mut()).await?;
+    let registry_token = crate::helpers::registry_token(
+      &stack.config.registry_provider,
+      &stack.config.registry_account,
     )
     .await
-    .map_err(Into::into)
+    .context("Failed to get registry token in call to db")?;
+
+    // Run the same secret interpolation a real deploy would, so the
+    // preview reflects final values. Nothing but file contents/compose
+    // config ever makes it into the returned diff, so sanitization here
+    // matches a real deploy's `update.logs`.
+    let secret_replacers = if !stack.config.skip_secret_interp {
+      let VariablesAndSecrets { variables, secrets } =
+        get_variables_and_secrets().await?;
+      let mut interpolator =
+        Interpolator::new(Some(&variables), &secrets);
+      interpolator.interpolate_stack(&mut stack)?;
+      if let Some(repo) = repo.as_mut() {
+        if !repo.config.skip_secret_interp {
+          interpolator.interpolate_repo(repo)?;
+        }
+      }
+      interpolator.secret_replacers
+    } else {
+      Default::default()
+    };
+
+    let GetComposeConfigResponse {
+      file_contents,
+      compose_config,
+      ..
+    } = periphery_client(&server)?
+      .request(GetComposeConfig {
+        stack: stack.clone(),
+        repo,
+        git_token,
+        registry_token,
+        replacers: secret_replacers.into_iter().collect(),
+      })
+      .await?;
+
+    let mut info = stack.info.clone();
+    info.remote_contents = Some(file_contents);
+
+    Ok(diff_stack_info(&info, &compose_config))
   }
 }